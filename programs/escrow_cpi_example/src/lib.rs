@@ -0,0 +1,223 @@
+// #[program] emits crate-level IDL handlers that call the deprecated AccountInfo::realloc
+#![allow(deprecated)]
+
+use anchor_lang::prelude::*;
+use anchor_spl::token::Token;
+use escrow_program::program::EscrowProgram;
+use escrow_program::InitializeEscrowArgs;
+
+declare_id!("H7A66cnQdobC8C86ud1oZwSr1mLPxgsnA32pKLNpU5wx");
+
+// Forwards to escrow_program through its `cpi` module; escrow_program runs every
+// check itself, so the accounts here are passed through unvalidated
+#[program]
+pub mod escrow_cpi_example {
+    use super::*;
+
+    pub fn initialize_escrow(
+        ctx: Context<InitializeEscrowViaCpi>,
+        args: InitializeEscrowArgs,
+    ) -> Result<()> {
+        let cpi_accounts = escrow_program::cpi::accounts::InitializeEscrow {
+            escrow: ctx.accounts.escrow.to_account_info(),
+            user_a: ctx.accounts.user_a.to_account_info(),
+            user_b: ctx.accounts.user_b.to_account_info(),
+            user_a_mint: ctx.accounts.user_a_mint.to_account_info(),
+            user_b_mint: ctx.accounts.user_b_mint.to_account_info(),
+            vault_a: ctx.accounts.vault_a.to_account_info(),
+            vault_b: ctx.accounts.vault_b.to_account_info(),
+            vault_b_payer: ctx.accounts.vault_b_payer.to_account_info(),
+            system_program: ctx.accounts.system_program.to_account_info(),
+            token_program: ctx.accounts.token_program.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new(ctx.accounts.escrow_program.to_account_info(), cpi_accounts);
+        escrow_program::cpi::initialize_escrow(cpi_ctx, args)
+    }
+
+    pub fn deposit(ctx: Context<DepositViaCpi>, amount: u64, side: u8) -> Result<()> {
+        let cpi_accounts = escrow_program::cpi::accounts::Deposit {
+            user: ctx.accounts.user.to_account_info(),
+            escrow: ctx.accounts.escrow.to_account_info(),
+            user_a_token: ctx
+                .accounts
+                .user_a_token
+                .as_ref()
+                .map(|account| account.to_account_info()),
+            user_b_token: ctx
+                .accounts
+                .user_b_token
+                .as_ref()
+                .map(|account| account.to_account_info()),
+            vault_a: ctx
+                .accounts
+                .vault_a
+                .as_ref()
+                .map(|account| account.to_account_info()),
+            vault_b: ctx
+                .accounts
+                .vault_b
+                .as_ref()
+                .map(|account| account.to_account_info()),
+            instructions_sysvar: ctx.accounts.instructions_sysvar.to_account_info(),
+            system_program: ctx.accounts.system_program.to_account_info(),
+            token_program: ctx.accounts.token_program.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new(ctx.accounts.escrow_program.to_account_info(), cpi_accounts);
+        escrow_program::cpi::deposit(cpi_ctx, amount, side)
+    }
+
+    // Funds the last side and settles from a single instruction. escrow_program
+    // rejects the execute when the escrow requires a separate settle transaction
+    pub fn deposit_and_execute(
+        ctx: Context<DepositAndExecuteViaCpi>,
+        amount: u64,
+        side: u8,
+    ) -> Result<()> {
+        let source = ctx.accounts.deposit_token.to_account_info();
+        let (user_a_token, user_b_token) = if side == escrow_program::Escrow::SIDE_A {
+            (Some(source), None)
+        } else {
+            (None, Some(source))
+        };
+        let deposit_accounts = escrow_program::cpi::accounts::Deposit {
+            user: ctx.accounts.user.to_account_info(),
+            escrow: ctx.accounts.escrow.to_account_info(),
+            user_a_token,
+            user_b_token,
+            vault_a: Some(ctx.accounts.vault_a.to_account_info()),
+            vault_b: Some(ctx.accounts.vault_b.to_account_info()),
+            instructions_sysvar: ctx.accounts.instructions_sysvar.to_account_info(),
+            system_program: ctx.accounts.system_program.to_account_info(),
+            token_program: ctx.accounts.token_program.to_account_info(),
+        };
+        let deposit_ctx = CpiContext::new(
+            ctx.accounts.escrow_program.to_account_info(),
+            deposit_accounts,
+        );
+        escrow_program::cpi::deposit(deposit_ctx, amount, side)?;
+
+        let execute_accounts = escrow_program::cpi::accounts::Execute {
+            caller: ctx.accounts.user.to_account_info(),
+            escrow: ctx.accounts.escrow.to_account_info(),
+            user_a: ctx.accounts.user_a.to_account_info(),
+            vault_b_payer: ctx.accounts.vault_b_payer.to_account_info(),
+            vault_a: ctx.accounts.vault_a.to_account_info(),
+            vault_b: ctx.accounts.vault_b.to_account_info(),
+            user_a_token: ctx.accounts.user_a_receive_token.to_account_info(),
+            user_b_token: ctx.accounts.user_b_receive_token.to_account_info(),
+            instructions_sysvar: ctx.accounts.instructions_sysvar.to_account_info(),
+            token_program: ctx.accounts.token_program.to_account_info(),
+        };
+        let execute_ctx = CpiContext::new(
+            ctx.accounts.escrow_program.to_account_info(),
+            execute_accounts,
+        );
+        escrow_program::cpi::execute(execute_ctx)
+    }
+}
+
+#[derive(Accounts)]
+pub struct InitializeEscrowViaCpi<'info> {
+    /// CHECK: initialized and validated by escrow_program
+    #[account(mut)]
+    pub escrow: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub user_a: Signer<'info>,
+    /// CHECK: just a public key, as in escrow_program
+    pub user_b: UncheckedAccount<'info>,
+
+    /// CHECK: validated by escrow_program
+    pub user_a_mint: UncheckedAccount<'info>,
+    /// CHECK: validated by escrow_program
+    pub user_b_mint: UncheckedAccount<'info>,
+
+    /// CHECK: initialized and validated by escrow_program
+    #[account(mut)]
+    pub vault_a: UncheckedAccount<'info>,
+    /// CHECK: initialized and validated by escrow_program
+    #[account(mut)]
+    pub vault_b: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub vault_b_payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub escrow_program: Program<'info, EscrowProgram>,
+}
+
+#[derive(Accounts)]
+pub struct DepositViaCpi<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    /// CHECK: validated by escrow_program
+    #[account(mut)]
+    pub escrow: UncheckedAccount<'info>,
+
+    /// CHECK: validated by escrow_program
+    #[account(mut)]
+    pub user_a_token: Option<UncheckedAccount<'info>>,
+    /// CHECK: validated by escrow_program
+    #[account(mut)]
+    pub user_b_token: Option<UncheckedAccount<'info>>,
+
+    /// CHECK: validated by escrow_program
+    #[account(mut)]
+    pub vault_a: Option<UncheckedAccount<'info>>,
+    /// CHECK: validated by escrow_program
+    #[account(mut)]
+    pub vault_b: Option<UncheckedAccount<'info>>,
+
+    /// CHECK: Instructions sysvar, address-checked
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    pub instructions_sysvar: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub escrow_program: Program<'info, EscrowProgram>,
+}
+
+#[derive(Accounts)]
+pub struct DepositAndExecuteViaCpi<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    /// CHECK: validated by escrow_program
+    #[account(mut)]
+    pub escrow: UncheckedAccount<'info>,
+
+    /// CHECK: validated by escrow_program
+    #[account(mut)]
+    pub deposit_token: UncheckedAccount<'info>,
+
+    /// CHECK: validated by escrow_program
+    #[account(mut)]
+    pub vault_a: UncheckedAccount<'info>,
+    /// CHECK: validated by escrow_program
+    #[account(mut)]
+    pub vault_b: UncheckedAccount<'info>,
+
+    /// CHECK: validated by escrow_program
+    #[account(mut)]
+    pub user_a: UncheckedAccount<'info>,
+    /// CHECK: validated by escrow_program
+    #[account(mut)]
+    pub vault_b_payer: UncheckedAccount<'info>,
+
+    /// CHECK: validated by escrow_program
+    #[account(mut)]
+    pub user_a_receive_token: UncheckedAccount<'info>,
+    /// CHECK: validated by escrow_program
+    #[account(mut)]
+    pub user_b_receive_token: UncheckedAccount<'info>,
+
+    /// CHECK: Instructions sysvar, address-checked
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    pub instructions_sysvar: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub escrow_program: Program<'info, EscrowProgram>,
+}
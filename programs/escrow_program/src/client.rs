@@ -0,0 +1,117 @@
+//! Helpers for programs and off-chain clients that read escrow accounts
+//! directly instead of through an Anchor `Account<Escrow>`.
+
+use anchor_lang::prelude::*;
+use anchor_lang::Discriminator;
+
+use crate::Escrow;
+
+/// Returns true if `data` starts with the `Escrow` account discriminator.
+///
+/// This only inspects the first eight bytes; callers holding an
+/// `AccountInfo` should prefer [`load_escrow`], which also checks the owner.
+pub fn has_escrow_discriminator(data: &[u8]) -> bool {
+    data.starts_with(Escrow::DISCRIMINATOR)
+}
+
+/// Loads an `Escrow` from an account, rejecting accounts not owned by this
+/// program or whose discriminator is not `Escrow`'s.
+///
+/// These are the same checks Anchor applies to `Account<'info, Escrow>`, which
+/// every instruction in this program uses for the escrow account.
+pub fn load_escrow(account: &AccountInfo) -> Result<Escrow> {
+    if *account.owner != crate::ID {
+        return Err(
+            error!(anchor_lang::error::ErrorCode::AccountOwnedByWrongProgram)
+                .with_pubkeys((*account.owner, crate::ID)),
+        );
+    }
+
+    let data = account.try_borrow_data()?;
+    Escrow::try_deserialize(&mut &data[..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every field of a freshly zeroed escrow borsh-decodes, which gives a
+    // valid starting point without spelling out each field
+    fn zeroed_escrow() -> Escrow {
+        let mut data = Escrow::DISCRIMINATOR.to_vec();
+        data.resize(Escrow::space(), 0);
+        Escrow::try_deserialize(&mut &data[..]).unwrap()
+    }
+
+    fn escrow_data(escrow: &Escrow) -> Vec<u8> {
+        let mut data = Vec::new();
+        escrow.try_serialize(&mut data).unwrap();
+        data
+    }
+
+    fn load_from(data: &mut [u8], owner: &Pubkey) -> Result<Escrow> {
+        let key = Pubkey::new_unique();
+        let mut lamports = 0;
+        let account = AccountInfo::new(&key, false, false, &mut lamports, data, owner, false, 0);
+        load_escrow(&account)
+    }
+
+    #[test]
+    fn discriminator_check_rejects_a_short_buffer() {
+        assert!(!has_escrow_discriminator(&Escrow::DISCRIMINATOR[..4]));
+    }
+
+    #[test]
+    fn discriminator_check_rejects_a_wrong_discriminator() {
+        let mut data = escrow_data(&zeroed_escrow());
+        data[0] ^= 0xff;
+        assert!(!has_escrow_discriminator(&data));
+    }
+
+    #[test]
+    fn discriminator_check_accepts_an_escrow() {
+        assert!(has_escrow_discriminator(&escrow_data(&zeroed_escrow())));
+    }
+
+    #[test]
+    fn load_escrow_rejects_a_short_buffer() {
+        let mut data = Escrow::DISCRIMINATOR[..4].to_vec();
+        assert_eq!(
+            load_from(&mut data, &crate::ID).err().unwrap(),
+            anchor_lang::error::ErrorCode::AccountDiscriminatorNotFound.into()
+        );
+    }
+
+    #[test]
+    fn load_escrow_rejects_a_wrong_discriminator() {
+        let mut data = escrow_data(&zeroed_escrow());
+        data[0] ^= 0xff;
+        assert_eq!(
+            load_from(&mut data, &crate::ID).err().unwrap(),
+            anchor_lang::error::ErrorCode::AccountDiscriminatorMismatch.into()
+        );
+    }
+
+    #[test]
+    fn load_escrow_rejects_the_wrong_owner() {
+        let mut data = escrow_data(&zeroed_escrow());
+        assert_eq!(
+            load_from(&mut data, &Pubkey::new_unique()).err().unwrap(),
+            anchor_lang::error::ErrorCode::AccountOwnedByWrongProgram.into()
+        );
+    }
+
+    #[test]
+    fn load_escrow_round_trips() {
+        let mut escrow = zeroed_escrow();
+        escrow.user_a = Pubkey::new_unique();
+        escrow.user_b = Pubkey::new_unique();
+        escrow.amount_a = 42;
+        escrow.terms_uri = "https://example.com/terms".to_string();
+
+        let mut data = escrow_data(&escrow);
+        let loaded = load_from(&mut data, &crate::ID).unwrap();
+        assert_eq!(escrow_data(&loaded), escrow_data(&escrow));
+        assert_eq!(loaded.amount_a, 42);
+    }
+}
@@ -0,0 +1,28 @@
+// #[program] emits crate-level IDL handlers that call the deprecated AccountInfo::realloc
+#![allow(deprecated)]
+
+use anchor_lang::prelude::*;
+
+declare_id!("4D8Zaey4jPFYHeqzJMo9AnYrjaaNNYGXBaGJuz2gjB9U");
+
+// Trivial target for escrow_program's post-settle hook; it only logs what it receives
+#[program]
+pub mod settle_callback {
+    use super::*;
+
+    pub fn on_settle(
+        _ctx: Context<OnSettle>,
+        user_a_balance: u64,
+        user_b_balance: u64,
+    ) -> Result<()> {
+        msg!(
+            "on_settle user_a_balance={} user_b_balance={}",
+            user_a_balance,
+            user_b_balance
+        );
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct OnSettle {}